@@ -1,153 +1,811 @@
-use anyhow::{anyhow, bail, ensure, Result};
+use anyhow::{bail, ensure, Result};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    io::Write,
     net::{SocketAddr, UdpSocket},
-    sync::Mutex,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::message::Message;
+use crate::crypto::{Handshake, Role, Session};
+use crate::message::{is_valid_name, Message};
+
+const RETRANSMIT_SCAN_INTERVAL: Duration = Duration::from_millis(200);
+const MIN_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(300);
+const MAX_RETRANSMIT_ATTEMPTS: u32 = 8;
+
+/// Largest `Text` body sent as a single fragment, comfortably under typical
+/// UDP path MTUs once the sequence number, AEAD tag, and framing overhead
+/// are added. Longer texts are split into several `TextFragment`s instead.
+const MAX_TEXT_FRAGMENT_BYTES: usize = 1024;
+
+/// Largest total `Text` a reassembled message may grow to once every
+/// fragment has arrived. Bounds how large a `count` we're willing to trust
+/// from an incoming `TextFragment`-which is peer-controlled-before
+/// allocating slots for it.
+const MAX_TEXT_MESSAGE_BYTES: usize = 1024 * 1024;
+const MAX_TEXT_FRAGMENTS: u32 = (MAX_TEXT_MESSAGE_BYTES / MAX_TEXT_FRAGMENT_BYTES) as u32;
+
+/// Splits `text` into chunks of at most `max_len` bytes without cutting a
+/// UTF-8 character in half.
+fn chunk_str(text: &str, max_len: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+
+    while !rest.is_empty() {
+        let mut split = max_len.min(rest.len());
+        while !rest.is_char_boundary(split) {
+            split -= 1;
+        }
+        let (chunk, remainder) = rest.split_at(split);
+        chunks.push(chunk);
+        rest = remainder;
+    }
+
+    chunks
+}
+
+/// Everything a `Client` tracks about one peer in the room: its encrypted
+/// session and the reliability bookkeeping for datagrams to and from it.
+#[derive(Debug)]
+struct PeerState {
+    session: Mutex<Option<Session>>,
+    send_counter: Mutex<u64>,
+    seen_counters: Mutex<HashSet<u64>>,
+
+    send_seq: Mutex<u32>,
+    unacked: Mutex<HashMap<u32, (Instant, Vec<u8>)>>,
+    unacked_attempts: Mutex<HashMap<u32, u32>>,
+    rtt: Mutex<Duration>,
+
+    next_recv_seq: Mutex<u32>,
+    reorder_buffer: Mutex<BTreeMap<u32, Message>>,
+
+    next_message_id: Mutex<u32>,
+    reassembly: Mutex<HashMap<u32, Vec<Option<String>>>>,
+}
+
+impl PeerState {
+    fn new(session: Session) -> Arc<Self> {
+        Arc::new(Self {
+            session: Mutex::new(Some(session)),
+            send_counter: Mutex::new(0),
+            seen_counters: Mutex::new(HashSet::new()),
+            send_seq: Mutex::new(0),
+            unacked: Mutex::new(HashMap::new()),
+            unacked_attempts: Mutex::new(HashMap::new()),
+            rtt: Mutex::new(MIN_RETRANSMIT_TIMEOUT),
+            next_recv_seq: Mutex::new(0),
+            reorder_buffer: Mutex::new(BTreeMap::new()),
+            next_message_id: Mutex::new(0),
+            reassembly: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Encrypts `plaintext`, prepending the per-packet encryption counter.
+    fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let session = self.session.lock().unwrap();
+        let session = session.as_ref().expect("peer always has a session");
+
+        let counter = {
+            let mut counter = self.send_counter.lock().unwrap();
+            let current = *counter;
+            *counter += 1;
+            current
+        };
+
+        let mut datagram = counter.to_be_bytes().to_vec();
+        datagram.append(&mut session.seal(counter, plaintext));
+        datagram
+    }
+
+    fn open(&self, datagram: &[u8]) -> Result<Vec<u8>> {
+        ensure!(datagram.len() >= 8, "Datagram too short to contain a sequence counter");
+
+        let mut counter_bytes = [0; 8];
+        counter_bytes.copy_from_slice(&datagram[..8]);
+        let counter = u64::from_be_bytes(counter_bytes);
+
+        ensure!(
+            self.seen_counters.lock().unwrap().insert(counter),
+            "Rejected replayed datagram"
+        );
+
+        let session = self.session.lock().unwrap();
+        let session = session.as_ref().expect("peer always has a session");
+        session.open(counter, &datagram[8..])
+    }
+
+    /// Assigns the next outbound sequence number to `message`, frames it as
+    /// `seq (4 bytes) || Message::into_bytes()`, and-unless it's itself an
+    /// `Ack`-remembers it for retransmission until the peer acknowledges it.
+    fn frame_for_delivery(&self, message: Message) -> Vec<u8> {
+        let seq = {
+            let mut seq = self.send_seq.lock().unwrap();
+            let current = *seq;
+            *seq = seq.wrapping_add(1);
+            current
+        };
+
+        let mut framed = seq.to_be_bytes().to_vec();
+        framed.extend(message.clone().into_bytes());
+
+        if !matches!(message, Message::Ack(_)) {
+            self.unacked
+                .lock()
+                .unwrap()
+                .insert(seq, (Instant::now(), framed.clone()));
+        }
+
+        framed
+    }
+
+    fn record_ack(&self, acked_seq: u32) {
+        if let Some((sent_at, _)) = self.unacked.lock().unwrap().remove(&acked_seq) {
+            self.unacked_attempts.lock().unwrap().remove(&acked_seq);
+            let mut rtt = self.rtt.lock().unwrap();
+            *rtt = (*rtt + sent_at.elapsed()) / 2;
+        }
+    }
+
+    /// Inserts a just-received sequenced message into the reorder buffer and
+    /// returns the contiguous run starting at the next expected sequence,
+    /// advancing it past everything returned.
+    fn release_in_order(&self, seq: u32, message: Message) -> Vec<Message> {
+        self.reorder_buffer.lock().unwrap().insert(seq, message);
+
+        let mut expected = self.next_recv_seq.lock().unwrap();
+        let mut buffer = self.reorder_buffer.lock().unwrap();
+
+        let mut released = Vec::new();
+        while let Some(message) = buffer.remove(&*expected) {
+            released.push(message);
+            *expected = expected.wrapping_add(1);
+        }
+        released
+    }
+
+    /// Splits an outbound `Text` larger than `MAX_TEXT_FRAGMENT_BYTES` into
+    /// several `TextFragment`s sharing a freshly allocated message id;
+    /// anything else is passed through as a single message.
+    fn fragment(&self, message: Message) -> Vec<Message> {
+        let Message::Text(text) = &message else {
+            return vec![message];
+        };
+
+        if text.len() <= MAX_TEXT_FRAGMENT_BYTES {
+            return vec![message];
+        }
+
+        let message_id = {
+            let mut next_message_id = self.next_message_id.lock().unwrap();
+            let id = *next_message_id;
+            *next_message_id = next_message_id.wrapping_add(1);
+            id
+        };
+
+        let chunks = chunk_str(text, MAX_TEXT_FRAGMENT_BYTES);
+        let count = chunks.len() as u32;
+
+        chunks
+            .into_iter()
+            .enumerate()
+            .map(|(index, chunk)| Message::TextFragment {
+                message_id,
+                index: index as u32,
+                count,
+                chunk: chunk.to_string(),
+            })
+            .collect()
+    }
+
+    /// Folds an incoming message through fragment reassembly: anything other
+    /// than `TextFragment` passes straight through; fragments accumulate
+    /// until every piece of their `message_id` has arrived, at which point
+    /// they're joined back into a single `Text`.
+    fn reassemble(&self, message: Message) -> Option<Message> {
+        let Message::TextFragment {
+            message_id,
+            index,
+            count,
+            chunk,
+        } = message
+        else {
+            return Some(message);
+        };
+
+        // `count`, `index` and the chunk come straight off the wire from the
+        // peer; without a cap, a single bogus fragment claiming an enormous
+        // count would force a multi-gigabyte allocation before a real chunk
+        // of text ever arrives. Capping each chunk's length as well keeps
+        // the reassembled text within `MAX_TEXT_MESSAGE_BYTES`.
+        if count == 0
+            || count > MAX_TEXT_FRAGMENTS
+            || index >= count
+            || chunk.len() > MAX_TEXT_FRAGMENT_BYTES
+        {
+            return None;
+        }
+
+        let mut reassembly = self.reassembly.lock().unwrap();
+        let slots = reassembly
+            .entry(message_id)
+            .or_insert_with(|| vec![None; count as usize]);
+
+        if let Some(slot) = slots.get_mut(index as usize) {
+            *slot = Some(chunk);
+        }
+
+        if slots.iter().all(Option::is_some) {
+            let text = slots.drain(..).map(|slot| slot.unwrap()).collect();
+            reassembly.remove(&message_id);
+            Some(Message::Text(text))
+        } else {
+            None
+        }
+    }
+}
+
+type Peers = Arc<Mutex<HashMap<SocketAddr, Arc<PeerState>>>>;
+type Names = Arc<Mutex<HashMap<SocketAddr, String>>>;
+type History = HashMap<SocketAddr, Vec<(bool, SystemTime, String)>>;
+
+/// Directory chat history is persisted under, one line-delimited file per
+/// peer address, so a client reloads prior conversations on reconnect.
+///
+/// Under `cargo test` this points at a process-unique directory under the
+/// OS temp dir instead of the real home directory, so the test suite can't
+/// litter a developer's actual `$HOME` or read back stale history left
+/// behind by an earlier run.
+fn history_dir() -> PathBuf {
+    #[cfg(test)]
+    {
+        std::env::temp_dir()
+            .join("itandddp-test-history")
+            .join(std::process::id().to_string())
+    }
+
+    #[cfg(not(test))]
+    {
+        std::env::var_os("HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from("."))
+            .join(".itandddp")
+            .join("history")
+    }
+}
+
+fn history_path(addr: SocketAddr) -> PathBuf {
+    history_dir().join(addr.to_string().replace(':', "_"))
+}
+
+fn load_history() -> History {
+    let mut history = History::new();
+
+    let Ok(entries) = std::fs::read_dir(history_dir()) else {
+        return history;
+    };
+
+    for entry in entries.flatten() {
+        let Some(file_name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        let Ok(addr) = file_name.replace('_', ":").parse::<SocketAddr>() else {
+            continue;
+        };
+        let Ok(contents) = std::fs::read_to_string(entry.path()) else {
+            continue;
+        };
+
+        let lines = contents
+            .lines()
+            .filter_map(|line| {
+                let mut parts = line.splitn(3, '\t');
+                let outgoing = parts.next()? == "1";
+                let secs: u64 = parts.next()?.parse().ok()?;
+                let text = parts.next()?.to_string();
+                Some((outgoing, UNIX_EPOCH + Duration::from_secs(secs), text))
+            })
+            .collect();
+
+        history.insert(addr, lines);
+    }
+
+    history
+}
 
 #[derive(Debug)]
 pub struct Client {
-    socket: UdpSocket,
-    chat_history: Mutex<HashMap<SocketAddr, Vec<(bool, String)>>>,
-    peer_addr: Mutex<Option<SocketAddr>>,
+    socket: Arc<UdpSocket>,
+    name: String,
+    chat_history: Mutex<History>,
+    peers: Peers,
+    names: Names,
+    ready_queue: Mutex<VecDeque<(SocketAddr, Message)>>,
 }
 
-impl Client {
-    pub fn new(socket: UdpSocket) -> Self {
-        Self {
-            socket,
-            peer_addr: Default::default(),
-            chat_history: Default::default(),
+/// Performs the connector side of the handshake against `addr`: exchanges
+/// keys and nicknames, registers the resulting session in `peers`, and
+/// mesh-connects (each in its own background thread) to everyone `addr`
+/// reports in its roster. Free-standing so it can run both for
+/// `Client::connect` and for the background threads that mesh-connect to
+/// the rest of a room.
+fn connect_peer(
+    socket: &Arc<UdpSocket>,
+    peers: &Peers,
+    names: &Names,
+    local_addr: SocketAddr,
+    local_name: &str,
+    addr: SocketAddr,
+) -> Result<()> {
+    ensure!(addr != local_addr, "Peer address can't be local address");
+
+    let handshake = Handshake::generate();
+    socket.send_to(
+        &Message::TryConnect(handshake.public.to_bytes()).into_bytes(),
+        addr,
+    )?;
+
+    let mut buf = [0; 65535];
+
+    let peer_public = loop {
+        let (number_of_bytes, src_addr) = socket.recv_from(&mut buf)?;
+
+        if src_addr != addr {
+            _ = socket.send_to(&Message::Unexpected.into_bytes(), src_addr);
+            continue;
+        }
+
+        // A trailing Ack from an earlier, already torn-down session with
+        // `addr` can still arrive here as unparseable encrypted bytes; skip
+        // it the same way wait_for_connection does.
+        let Ok(message) = Message::from_bytes(&buf[..number_of_bytes]) else {
+            continue;
+        };
+
+        match message {
+            Message::SuccesfullyConnected(peer_public) => break peer_public,
+            Message::Unexpected => bail!("Server isn't waiting for connection"),
+            other => bail!("Expected SuccesfullyConnected, but got {other:?}"),
         }
+    };
+
+    // The shared secret is known as soon as the peer's public key arrives,
+    // so the session is built here and used for everything from this point
+    // on-nicknames included-rather than waiting until after the handshake
+    // fully wraps up.
+    let peer = PeerState::new(handshake.complete(peer_public, Role::Initiator));
+
+    socket.send_to(&peer.seal(&Message::Hello(local_name.to_string()).into_bytes()), addr)?;
+
+    let peer_name = loop {
+        let (number_of_bytes, src_addr) = socket.recv_from(&mut buf)?;
+
+        if src_addr != addr {
+            continue;
+        }
+
+        match Message::from_bytes(&peer.open(&buf[..number_of_bytes])?)? {
+            Message::Hello(name) => {
+                ensure!(is_valid_name(&name), "Peer sent an invalid nickname");
+                break name;
+            }
+            other => bail!("Expected Hello, but got {other:?}"),
+        }
+    };
+
+    let roster = loop {
+        let (number_of_bytes, src_addr) = socket.recv_from(&mut buf)?;
+
+        if src_addr != addr {
+            continue;
+        }
+
+        match Message::from_bytes(&peer.open(&buf[..number_of_bytes])?)? {
+            Message::Roster(addrs) => break addrs,
+            other => bail!("Expected Roster, but got {other:?}"),
+        }
+    };
+
+    peers.lock().unwrap().insert(addr, peer);
+    names.lock().unwrap().insert(addr, peer_name);
+
+    for peer_addr in roster {
+        if peer_addr == local_addr || peers.lock().unwrap().contains_key(&peer_addr) {
+            continue;
+        }
+
+        let socket = socket.clone();
+        let peers = peers.clone();
+        let names = names.clone();
+        let local_name = local_name.to_string();
+        std::thread::spawn(move || {
+            _ = connect_peer(&socket, &peers, &names, local_addr, &local_name, peer_addr)
+        });
+    }
+
+    Ok(())
+}
+
+impl Client {
+    pub fn new(socket: UdpSocket, name: String) -> Self {
+        let client = Self {
+            socket: Arc::new(socket),
+            name,
+            chat_history: Mutex::new(load_history()),
+            peers: Default::default(),
+            names: Default::default(),
+            ready_queue: Default::default(),
+        };
+
+        client.spawn_retransmitter();
+
+        client
+    }
+
+    fn spawn_retransmitter(&self) {
+        let socket = self.socket.clone();
+        let peers = self.peers.clone();
+
+        std::thread::spawn(move || loop {
+            std::thread::sleep(RETRANSMIT_SCAN_INTERVAL);
+
+            let snapshot: Vec<(SocketAddr, Arc<PeerState>)> = peers
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(&addr, peer)| (addr, peer.clone()))
+                .collect();
+
+            for (addr, peer) in snapshot {
+                let timeout = (*peer.rtt.lock().unwrap() * 2).max(MIN_RETRANSMIT_TIMEOUT);
+
+                let due: Vec<(u32, Vec<u8>)> = peer
+                    .unacked
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .filter(|(_, (sent_at, _))| sent_at.elapsed() >= timeout)
+                    .map(|(&seq, (_, framed))| (seq, framed.clone()))
+                    .collect();
+
+                for (seq, framed) in due {
+                    let attempts = {
+                        let mut attempts = peer.unacked_attempts.lock().unwrap();
+                        let count = attempts.entry(seq).or_insert(0);
+                        *count += 1;
+                        *count
+                    };
+
+                    if attempts > MAX_RETRANSMIT_ATTEMPTS {
+                        peer.unacked.lock().unwrap().remove(&seq);
+                        peer.unacked_attempts.lock().unwrap().remove(&seq);
+                        peers.lock().unwrap().remove(&addr);
+                        continue;
+                    }
+
+                    let datagram = peer.seal(&framed);
+                    _ = socket.send_to(&datagram, addr);
+
+                    if let Some((sent_at, _)) = peer.unacked.lock().unwrap().get_mut(&seq) {
+                        *sent_at = Instant::now();
+                    }
+                }
+            }
+        });
     }
 
     pub fn address(&self) -> SocketAddr {
         self.socket.local_addr().unwrap()
     }
 
-    pub fn peer_addr(&self) -> Result<SocketAddr> {
-        self.peer_addr
-            .lock()
-            .unwrap()
-            .ok_or(anyhow!("Disconnected"))
+    pub fn is_connected(&self) -> bool {
+        !self.peers.lock().unwrap().is_empty()
     }
 
-    fn set_peer_addr(&self, addr: Option<SocketAddr>) {
-        *self.peer_addr.lock().unwrap() = addr;
+    /// Every peer currently in the room, in no particular order.
+    pub fn peers(&self) -> Vec<SocketAddr> {
+        self.peers.lock().unwrap().keys().copied().collect()
     }
 
-    pub fn is_connected(&self) -> bool {
-        self.peer_addr().is_ok()
+    fn peer(&self, addr: SocketAddr) -> Option<Arc<PeerState>> {
+        self.peers.lock().unwrap().get(&addr).cloned()
+    }
+
+    /// The nickname a peer gave us during its handshake.
+    pub fn peer_name(&self, addr: SocketAddr) -> Option<String> {
+        self.names.lock().unwrap().get(&addr).cloned()
     }
 
-    fn save_to_history(&self, outgoing: bool, message: Message) {
+    fn save_to_history(&self, outgoing: bool, addr: SocketAddr, message: Message) {
         if let Message::Text(text) = message {
-            if let Ok(mut history) = self.chat_history.lock() {
-                let key = self.peer_addr().unwrap();
-                if let Some(entry) = history.get_mut(&key) {
-                    entry.push((outgoing, text));
-                } else {
-                    history.insert(key, vec![(outgoing, text)]);
-                }
-            }
+            let time = SystemTime::now();
+
+            self.chat_history
+                .lock()
+                .unwrap()
+                .entry(addr)
+                .or_default()
+                .push((outgoing, time, text.clone()));
+
+            self.append_to_history_file(outgoing, addr, time, &text);
+        }
+    }
+
+    fn append_to_history_file(
+        &self,
+        outgoing: bool,
+        addr: SocketAddr,
+        time: SystemTime,
+        text: &str,
+    ) {
+        let Ok(()) = std::fs::create_dir_all(history_dir()) else {
+            return;
         };
+
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let line = format!("{}\t{secs}\t{text}\n", outgoing as u8);
+
+        if let Ok(mut file) = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(history_path(addr))
+        {
+            _ = file.write_all(line.as_bytes());
+        }
+    }
+
+    /// The conversation with a single peer.
+    pub fn history(&self, addr: SocketAddr) -> Option<Vec<(bool, SystemTime, String)>> {
+        Some(self.chat_history.lock().unwrap().get(&addr)?.clone())
+    }
+
+    /// Deletes a peer's conversation, both in memory and on disk.
+    pub fn clear_history(&self, addr: SocketAddr) {
+        self.chat_history.lock().unwrap().remove(&addr);
+        _ = std::fs::remove_file(history_path(addr));
     }
 
-    pub fn history(&self) -> Option<Vec<(bool, String)>> {
-        let history = self.chat_history.lock().ok()?;
-        let key = self.peer_addr().ok()?;
-        Some(history.get(&key)?[..].to_owned())
+    /// The conversation with every peer, merged into a single timeline and
+    /// labeled with who each line is with.
+    pub fn combined_history(&self) -> Vec<(SocketAddr, bool, SystemTime, String)> {
+        let mut history: Vec<_> = self
+            .chat_history
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(&addr, lines)| {
+                lines
+                    .iter()
+                    .map(move |(outgoing, time, text)| (addr, *outgoing, *time, text.clone()))
+            })
+            .collect();
+
+        history.sort_by_key(|&(_, _, time, _)| time);
+        history
     }
 
     fn send_to(&self, message: Message, addr: SocketAddr) -> Result<()> {
-        self.save_to_history(true, message.clone());
-        self.socket.send_to(&message.into_bytes(), addr)?;
+        let Some(peer) = self.peer(addr) else {
+            bail!("{addr} is not a connected peer");
+        };
+
+        self.save_to_history(true, addr, message.clone());
+
+        for fragment in peer.fragment(message) {
+            let datagram = peer.seal(&peer.frame_for_delivery(fragment));
+            self.socket.send_to(&datagram, addr)?;
+        }
+
         Ok(())
     }
 
-    pub fn send<M: Into<Message>>(&self, message: M) -> Result<()> {
-        self.send_to(message.into(), self.peer_addr()?)?;
-        Ok(())
+    pub fn send<M: Into<Message>>(&self, message: M, addr: SocketAddr) -> Result<()> {
+        self.send_to(message.into(), addr)
     }
 
-    fn recv(&self) -> Result<Message> {
-        let mut buf = [0; 65535];
+    /// Sends `message` to every peer currently in the room.
+    pub fn broadcast<M: Into<Message>>(&self, message: M) -> Result<()> {
+        let message = message.into();
+        for addr in self.peers() {
+            self.send_to(message.clone(), addr)?;
+        }
+        Ok(())
+    }
 
-        let (size, addr) = self.socket.recv_from(&mut buf)?;
+    fn broadcast_except<M: Into<Message>>(&self, message: M, exclude: SocketAddr) -> Result<()> {
+        let message = message.into();
+        for addr in self.peers() {
+            if addr != exclude {
+                self.send_to(message.clone(), addr)?;
+            }
+        }
+        Ok(())
+    }
 
-        if addr != self.peer_addr()? {
-            self.send_to(Message::Unexpected, addr)?;
-            self.recv()
-        } else {
-            let message = Message::from_bytes(&buf[..size])?;
-            self.save_to_history(false, message.clone());
+    fn register_peer(&self, addr: SocketAddr, peer: Arc<PeerState>) {
+        self.peers.lock().unwrap().insert(addr, peer);
+    }
 
-            Ok(message)
-        }
+    fn remove_peer(&self, addr: SocketAddr) {
+        self.peers.lock().unwrap().remove(&addr);
+        self.names.lock().unwrap().remove(&addr);
     }
 
-    pub fn recv_text(&self) -> Result<String> {
-        let message = self.recv()?;
+    /// Accepts an incoming `TryConnect` from `addr`: completes the
+    /// handshake, exchanges nicknames, tells `addr` about everyone else
+    /// already in the room, adds it to the room, and tells everyone else
+    /// about `addr`.
+    fn accept_peer(&self, addr: SocketAddr, peer_public: [u8; 32]) -> Result<()> {
+        let handshake = Handshake::generate();
+        self.socket.send_to(
+            &Message::SuccesfullyConnected(handshake.public.to_bytes()).into_bytes(),
+            addr,
+        )?;
 
-        match message {
-            Message::Text(text) => Ok(text),
-            Message::Disconnect | Message::SuccesfullyDisonnected => {
-                if message == Message::Disconnect {
-                    _ = self.send(Message::SuccesfullyDisonnected);
-                };
-                self.set_peer_addr(None);
-                bail!("Disconnected");
+        // The shared secret is known as soon as the peer's public key
+        // arrives, so the session is built here and used for everything
+        // from this point on-nicknames included-rather than waiting until
+        // after the handshake fully wraps up.
+        let peer = PeerState::new(handshake.complete(peer_public, Role::Responder));
+
+        self.socket
+            .send_to(&peer.seal(&Message::Hello(self.name.clone()).into_bytes()), addr)?;
+
+        let mut buf = [0; 65535];
+        let peer_name = loop {
+            let (number_of_bytes, src_addr) = self.socket.recv_from(&mut buf)?;
+
+            if src_addr != addr {
+                continue;
             }
-            _ => bail!("Unexpected message: {message}"),
-        }
+
+            match Message::from_bytes(&peer.open(&buf[..number_of_bytes])?)? {
+                Message::Hello(name) => {
+                    ensure!(is_valid_name(&name), "Peer sent an invalid nickname");
+                    break name;
+                }
+                other => bail!("Expected Hello, but got {other:?}"),
+            }
+        };
+
+        let roster = self.peers();
+        self.socket
+            .send_to(&peer.seal(&Message::Roster(roster).into_bytes()), addr)?;
+
+        self.register_peer(addr, peer);
+        self.names.lock().unwrap().insert(addr, peer_name);
+        self.broadcast_except(Message::Join(addr), addr)?;
+
+        Ok(())
     }
 
+    /// Blocks until someone connects, accepting them into the room. Once a
+    /// client has called this (or `connect`) once, later peers mesh-connect
+    /// to it automatically without calling this again-see `recv`.
     pub fn wait_for_connection(&self) -> Result<()> {
-        let mut buf = [0; 2];
+        let mut buf = [0; 65535];
 
-        let (number_of_bytes, addr) = self.socket.recv_from(&mut buf)?;
+        loop {
+            let (number_of_bytes, addr) = self.socket.recv_from(&mut buf)?;
 
-        match Message::from_bytes(&buf[..number_of_bytes])? {
-            Message::TryConnect => {
-                self.send_to(Message::SuccesfullyConnected, addr)?;
-                self.set_peer_addr(Some(addr));
-                Ok(())
+            // Stray datagrams from unknown or former peers-e.g. a trailing
+            // Ack for a SuccesfullyDisonnected that was still in flight when
+            // its session got torn down-land here as undecryptable or
+            // unparseable bytes. Ignore them and keep waiting, the same way
+            // recv()'s main loop tolerates anything that isn't a TryConnect
+            // from a stranger, rather than bailing on the first stray byte.
+            if let Ok(Message::TryConnect(peer_public)) =
+                Message::from_bytes(&buf[..number_of_bytes])
+            {
+                return self.accept_peer(addr, peer_public);
             }
-            other => bail!("Expected TryConnect, but got {other:?}"),
         }
     }
 
+    /// Connects to `addr` and mesh-connects to everyone already in its room.
     pub fn connect<A: Into<SocketAddr>>(&self, addr: A) -> Result<()> {
-        let addr = addr.into();
-
-        ensure!(
-            addr != self.address(),
-            "Peer address can't be local address"
-        );
+        connect_peer(
+            &self.socket,
+            &self.peers,
+            &self.names,
+            self.address(),
+            &self.name,
+            addr.into(),
+        )
+    }
 
-        self.send_to(Message::TryConnect, addr)?;
+    fn recv(&self) -> Result<(SocketAddr, Message)> {
+        if let Some(entry) = self.ready_queue.lock().unwrap().pop_front() {
+            return Ok(entry);
+        }
 
-        let mut buf = [0; 2];
+        let mut buf = [0; 65535];
 
         loop {
-            let (number_of_bytes, src_addr) = self.socket.recv_from(&mut buf)?;
+            let (size, addr) = self.socket.recv_from(&mut buf)?;
 
-            if src_addr == addr {
-                return match Message::from_bytes(&buf[..number_of_bytes])? {
-                    Message::SuccesfullyConnected => {
-                        self.set_peer_addr(Some(addr));
-                        Ok(())
-                    }
-                    Message::Unexpected => {
-                        bail!("Server isn't waiting for connection")
+            let Some(peer) = self.peer(addr) else {
+                // Not a known peer yet: the only thing we accept from a
+                // stranger is a mesh-connect request.
+                if let Ok(Message::TryConnect(peer_public)) = Message::from_bytes(&buf[..size]) {
+                    _ = self.accept_peer(addr, peer_public);
+                } else {
+                    _ = self.socket.send_to(&Message::Unexpected.into_bytes(), addr);
+                }
+                continue;
+            };
+
+            let plaintext = peer.open(&buf[..size])?;
+            ensure!(
+                plaintext.len() >= 4,
+                "Datagram too short to contain a sequence number"
+            );
+
+            let mut seq_bytes = [0; 4];
+            seq_bytes.copy_from_slice(&plaintext[..4]);
+            let seq = u32::from_be_bytes(seq_bytes);
+
+            let message = Message::from_bytes(&plaintext[4..])?;
+            let is_ack = matches!(message, Message::Ack(_));
+
+            // Acks still consume a slot in the peer's ordered sequence space
+            // (frame_for_delivery assigned them one), so they must still go
+            // through release_in_order to advance next_recv_seq past them-
+            // otherwise the next real message sent by this peer would wait
+            // forever for a sequence number that already went by. They're
+            // just never themselves handed to the caller.
+            if !is_ack {
+                self.send_to(Message::Ack(seq), addr)?;
+            }
+
+            let mut released = peer
+                .release_in_order(seq, message)
+                .into_iter()
+                .filter_map(|message| match message {
+                    Message::Ack(acked_seq) => {
+                        peer.record_ack(acked_seq);
+                        None
                     }
-                    other => bail!("Expected SuccesfullyConnected, but got {other:?}"),
+                    message => peer.reassemble(message),
+                });
+
+            let Some(first) = released.next() else {
+                continue;
+            };
+
+            let mut queue = self.ready_queue.lock().unwrap();
+            for message in released {
+                self.save_to_history(false, addr, message.clone());
+                queue.push_back((addr, message));
+            }
+            drop(queue);
+
+            self.save_to_history(false, addr, first.clone());
+            return Ok((addr, first));
+        }
+    }
+
+    pub fn recv_text(&self) -> Result<(SocketAddr, String)> {
+        let (addr, message) = self.recv()?;
+
+        match message {
+            Message::Text(text) => Ok((addr, text)),
+            Message::Disconnect | Message::SuccesfullyDisonnected => {
+                if message == Message::Disconnect {
+                    _ = self.send(Message::SuccesfullyDisonnected, addr);
                 };
-            } else {
-                _ = self.send_to(Message::Unexpected, src_addr);
+                self.remove_peer(addr);
+                _ = self.broadcast_except(Message::Leave(addr), addr);
+                bail!("{addr} disconnected");
+            }
+            Message::Join(joined) => bail!("{joined} joined the room"),
+            Message::Leave(left) => {
+                self.remove_peer(left);
+                bail!("{left} left the room");
             }
+            other => bail!("Unexpected message: {other}"),
         }
     }
 }
@@ -155,6 +813,6 @@ impl Client {
 impl Default for Client {
     fn default() -> Self {
         let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
-        Self::new(socket)
+        Self::new(socket, "Anonymous".to_string())
     }
 }
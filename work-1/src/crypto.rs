@@ -0,0 +1,114 @@
+use anyhow::{anyhow, Result};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
+
+/// One side of an in-progress X25519 key exchange.
+///
+/// Generated fresh for every `connect`/`wait_for_connection` attempt and
+/// consumed by `complete` once the peer's public key arrives, since the
+/// underlying secret can only be used for a single Diffie-Hellman.
+pub struct Handshake {
+    secret: EphemeralSecret,
+    pub public: PublicKey,
+}
+
+impl Handshake {
+    pub fn generate() -> Self {
+        let secret = EphemeralSecret::random_from_rng(rand_core::OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn complete(self, peer_public: [u8; 32], role: Role) -> Session {
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer_public));
+        Session::derive(&shared, role)
+    }
+}
+
+/// Which side of a handshake a peer played, so the two directions of a
+/// session can be keyed separately-otherwise both sides would seal their
+/// first datagram (counter 0) under the same key and nonce.
+#[derive(Debug, Clone, Copy)]
+pub enum Role {
+    Initiator,
+    Responder,
+}
+
+/// An established ChaCha20-Poly1305 session with a peer.
+///
+/// Each direction gets its own key and nonce space, derived from the shared
+/// secret with a role-specific HKDF label, so the initiator's and
+/// responder's first packets never reuse a nonce under the same key. Every
+/// datagram is sealed with a per-packet nonce formed from the relevant
+/// `base_nonce` XORed with a counter the caller is responsible for tracking
+/// (the send counter and the set of counters already seen on the receive
+/// side).
+pub struct Session {
+    send_cipher: ChaCha20Poly1305,
+    send_base_nonce: [u8; 12],
+    recv_cipher: ChaCha20Poly1305,
+    recv_base_nonce: [u8; 12],
+}
+
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Session { .. }")
+    }
+}
+
+impl Session {
+    fn derive(shared_secret: &SharedSecret, role: Role) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
+
+        let initiator_to_responder =
+            Self::expand(&hkdf, b"itandddp session key: initiator->responder");
+        let responder_to_initiator =
+            Self::expand(&hkdf, b"itandddp session key: responder->initiator");
+
+        let ((send_cipher, send_base_nonce), (recv_cipher, recv_base_nonce)) = match role {
+            Role::Initiator => (initiator_to_responder, responder_to_initiator),
+            Role::Responder => (responder_to_initiator, initiator_to_responder),
+        };
+
+        Self {
+            send_cipher,
+            send_base_nonce,
+            recv_cipher,
+            recv_base_nonce,
+        }
+    }
+
+    fn expand(hkdf: &Hkdf<Sha256>, info: &[u8]) -> (ChaCha20Poly1305, [u8; 12]) {
+        let mut okm = [0; 44];
+        hkdf.expand(info, &mut okm)
+            .expect("44 is a valid Sha256 HKDF output length");
+
+        let key = Key::from_slice(&okm[..32]);
+        let mut base_nonce = [0; 12];
+        base_nonce.copy_from_slice(&okm[32..44]);
+
+        (ChaCha20Poly1305::new(key), base_nonce)
+    }
+
+    fn nonce_for(base_nonce: &[u8; 12], counter: u64) -> Nonce {
+        let mut nonce = *base_nonce;
+        for (byte, counter_byte) in nonce[4..].iter_mut().zip(counter.to_be_bytes()) {
+            *byte ^= counter_byte;
+        }
+        *Nonce::from_slice(&nonce)
+    }
+
+    pub fn seal(&self, counter: u64, plaintext: &[u8]) -> Vec<u8> {
+        self.send_cipher
+            .encrypt(&Self::nonce_for(&self.send_base_nonce, counter), plaintext)
+            .expect("sealing a datagram does not fail")
+    }
+
+    pub fn open(&self, counter: u64, ciphertext: &[u8]) -> Result<Vec<u8>> {
+        self.recv_cipher
+            .decrypt(&Self::nonce_for(&self.recv_base_nonce, counter), ciphertext)
+            .map_err(|_| anyhow!("Failed to decrypt datagram"))
+    }
+}
@@ -1,4 +1,5 @@
 mod client;
+mod crypto;
 mod message;
 
 use anyhow::Result;
@@ -27,6 +28,20 @@ fn connect_or_listen(client: &Client, input: &str) -> Result<()> {
 }
 
 fn create_client() -> Client {
+    println!("Write a nickname to chat under");
+    let name = loop {
+        let mut buf = String::new();
+
+        _ = std::io::stdin().read_line(&mut buf);
+        buf.remove(buf.len() - 1);
+
+        if message::is_valid_name(&buf) {
+            break buf;
+        }
+
+        println!("Nicknames can't be empty or contain whitespace");
+    };
+
     println!("Write a port number you want to use, or click enter to use default");
     loop {
         let mut buf = String::new();
@@ -37,10 +52,11 @@ fn create_client() -> Client {
         dbg!(&buf);
 
         if buf.is_empty() {
-            return Client::default();
+            let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            return Client::new(socket, name);
         } else {
             match std::net::UdpSocket::bind(format!("127.0.0.1:{buf}")) {
-                Ok(socket) => return Client::new(socket),
+                Ok(socket) => return Client::new(socket, name),
                 Err(e) => println!("Can't use port {buf}: {e}"),
             }
         }
@@ -53,18 +69,27 @@ fn greeting(client: &Client) {
     println!("Write a target address or port you want to chat with or type 'listen'");
 }
 
+fn format_time(time: std::time::SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (hours, minutes, seconds) = ((secs / 3600) % 24, (secs / 60) % 60, secs % 60);
+    format!("{hours:02}:{minutes:02}:{seconds:02}")
+}
+
 fn open_chat(client: &Client) {
     clear();
-    println!("connected to {}", client.peer_addr().unwrap());
-
-    if let Some(history) = client.history() {
-        history.iter().for_each(|(outgoing, text)| {
-            if *outgoing {
-                println!("{text}");
-            } else {
-                println!("Message: {text}");
-            }
-        })
+    println!("connected to {:?}", client.peers());
+
+    for (addr, outgoing, time, text) in client.combined_history() {
+        let time = format_time(time);
+        if outgoing {
+            println!("[{time}] {text}");
+        } else {
+            let name = client.peer_name(addr).unwrap_or_else(|| addr.to_string());
+            println!("[{time}] {name}: {text}");
+        }
     }
 }
 
@@ -72,8 +97,10 @@ fn spawn_input_message_thread(client: &Arc<Client>) {
     let client = client.clone();
     std::thread::spawn(move || loop {
         match client.recv_text() {
-            Ok(message) => {
-                println!("Message: {}", message);
+            Ok((addr, message)) => {
+                let name = client.peer_name(addr).unwrap_or_else(|| addr.to_string());
+                let time = format_time(std::time::SystemTime::now());
+                println!("[{time}] {name}: {message}");
             }
             Err(err) => {
                 if !client.is_connected() {
@@ -98,7 +125,14 @@ fn main() {
         std::thread::spawn(move || {
             while let Ok(text) = receiver.recv() {
                 if client.is_connected() {
-                    if let Err(e) = client.send(text) {
+                    if text == "/history" {
+                        open_chat(&client);
+                    } else if text == "/history clear" {
+                        for addr in client.peers() {
+                            client.clear_history(addr);
+                        }
+                        open_chat(&client);
+                    } else if let Err(e) = client.broadcast(text) {
                         println!("Can't send message: {e}")
                     }
                 } else {
@@ -122,7 +156,7 @@ fn main() {
         }
 
         if client.is_connected() {
-            client.send(Disconnect).unwrap();
+            client.broadcast(Disconnect).unwrap();
         }
     }
 }
@@ -152,16 +186,17 @@ mod tests {
         let client2 = Client::default();
 
         let addr1 = client1.address();
+        let addr2 = client2.address();
         let handle = std::thread::spawn(move || {
             client2.connect(addr1).unwrap();
 
-            client2.send("Hello").unwrap();
-            assert_eq!(&client2.recv_text().unwrap(), "Bye");
+            client2.send("Hello", addr1).unwrap();
+            assert_eq!(client2.recv_text().unwrap(), (addr1, "Bye".to_string()));
         });
 
         client1.wait_for_connection().unwrap();
-        assert_eq!(&client1.recv_text().unwrap(), "Hello");
-        client1.send("Bye").unwrap();
+        assert_eq!(client1.recv_text().unwrap(), (addr2, "Hello".to_string()));
+        client1.send("Bye", addr2).unwrap();
 
         handle.join().unwrap();
     }
@@ -172,19 +207,20 @@ mod tests {
         let client2 = Client::default();
 
         let addr1 = client1.address();
+        let addr2 = client2.address();
         let handle = std::thread::spawn(move || {
             client2.connect(addr1).unwrap();
 
-            client2.send("Hello").unwrap();
+            client2.send("Hello", addr1).unwrap();
 
             assert!(client2.recv_text().is_err());
             assert!(!client2.is_connected());
         });
 
         client1.wait_for_connection().unwrap();
-        assert_eq!(&client1.recv_text().unwrap(), "Hello");
+        assert_eq!(client1.recv_text().unwrap(), (addr2, "Hello".to_string()));
 
-        client1.send(Disconnect).unwrap();
+        client1.send(Disconnect, addr2).unwrap();
 
         assert!(client1.recv_text().is_err());
         assert!(!client1.is_connected());
@@ -202,32 +238,42 @@ mod tests {
         let handle = std::thread::spawn(move || {
             client2.connect(addr1).unwrap();
 
-            client2.send("Hello").unwrap();
-            assert_eq!(&client2.recv_text().unwrap(), "Bye");
+            client2.send("Hello", addr1).unwrap();
+            assert_eq!(client2.recv_text().unwrap(), (addr1, "Bye".to_string()));
 
             assert!(client2.recv_text().is_err());
             assert!(!client2.is_connected());
 
             client2.wait_for_connection().unwrap();
 
-            let history = client2.history().unwrap();
-            assert_eq!(*history, [(true, "Hello".into()), (false, "Bye".into())]);
+            let history: Vec<(bool, String)> = client2
+                .history(addr1)
+                .unwrap()
+                .into_iter()
+                .map(|(outgoing, _, text)| (outgoing, text))
+                .collect();
+            assert_eq!(history, [(true, "Hello".into()), (false, "Bye".into())]);
         });
 
         client1.wait_for_connection().unwrap();
-        assert_eq!(&client1.recv_text().unwrap(), "Hello");
+        assert_eq!(client1.recv_text().unwrap(), (addr2, "Hello".to_string()));
 
-        client1.send("Bye").unwrap();
+        client1.send("Bye", addr2).unwrap();
 
-        client1.send(Disconnect).unwrap();
+        client1.send(Disconnect, addr2).unwrap();
 
         assert!(client1.recv_text().is_err());
         assert!(!client1.is_connected());
 
         client1.connect(addr2).unwrap();
 
-        let history = client1.history().unwrap();
-        assert_eq!(*history, [(false, "Hello".into()), (true, "Bye".into())]);
+        let history: Vec<(bool, String)> = client1
+            .history(addr2)
+            .unwrap()
+            .into_iter()
+            .map(|(outgoing, _, text)| (outgoing, text))
+            .collect();
+        assert_eq!(history, [(false, "Hello".into()), (true, "Bye".into())]);
 
         handle.join().unwrap();
     }
@@ -253,7 +299,7 @@ mod tests {
                 client2.recv_text().unwrap_err();
 
                 client2.connect(addr1).unwrap();
-                client2.send(Disconnect).unwrap();
+                client2.send(Disconnect, addr1).unwrap();
                 client2.recv_text().unwrap_err();
 
                 client2.wait_for_connection().unwrap();
@@ -261,7 +307,7 @@ mod tests {
         ];
 
         client3.wait_for_connection().unwrap();
-        client3.send(Disconnect).unwrap();
+        client3.send(Disconnect, addr2).unwrap();
         client3.recv_text().unwrap_err();
         assert!(!client3.is_connected());
 
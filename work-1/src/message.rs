@@ -1,11 +1,13 @@
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, ensure, Result};
+use std::net::{Ipv4Addr, SocketAddr};
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Message {
-    // Connection request
-    TryConnect,
-    // Answer to TryConnect if you want to connect
-    SuccesfullyConnected,
+    // Connection request, carrying the sender's ephemeral X25519 public key
+    TryConnect([u8; 32]),
+    // Answer to TryConnect if you want to connect, carrying the responder's
+    // own ephemeral X25519 public key
+    SuccesfullyConnected([u8; 32]),
     // Client is not connected
     Unexpected,
     // Close chat
@@ -14,6 +16,31 @@ pub enum Message {
     SuccesfullyDisonnected,
     // Text message
     Text(String),
+    // Acknowledges receipt of the message with the given sequence number
+    Ack(u32),
+    // Sent to the existing members of a room when a new peer joins
+    Join(SocketAddr),
+    // Sent to the remaining members of a room when a peer leaves
+    Leave(SocketAddr),
+    // Sent to a newly connected peer with the addresses of everyone else
+    // already in the room, so it can mesh-connect to them directly
+    Roster(Vec<SocketAddr>),
+    // Sent right after SuccesfullyConnected, carrying the sender's nickname
+    Hello(String),
+    // One piece of a `Text` too large for a single datagram; `index` and
+    // `count` place it within the `count` fragments sharing `message_id`
+    TextFragment {
+        message_id: u32,
+        index: u32,
+        count: u32,
+        chunk: String,
+    },
+}
+
+/// A nickname is valid for `Message::Hello` if it's non-empty and contains
+/// no whitespace, so it can never be confused with the rest of a chat line.
+pub(crate) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(char::is_whitespace)
 }
 
 impl<S> From<S> for Message
@@ -28,44 +55,187 @@ where
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Message::TryConnect => write!(f, "Connection request"),
-            Message::SuccesfullyConnected => write!(f, "Succesfully connected"),
+            Message::TryConnect(_) => write!(f, "Connection request"),
+            Message::SuccesfullyConnected(_) => write!(f, "Succesfully connected"),
             Message::Unexpected => write!(f, "Unexpected message"),
             Message::Disconnect => write!(f, "Disconnect"),
             Message::SuccesfullyDisonnected => write!(f, "Succesfully disconnected"),
             Message::Text(text) => write!(f, "Message: {text}"),
+            Message::Ack(seq) => write!(f, "Ack({seq})"),
+            Message::Join(addr) => write!(f, "{addr} joined"),
+            Message::Leave(addr) => write!(f, "{addr} left"),
+            Message::Roster(addrs) => write!(f, "Roster({addrs:?})"),
+            Message::Hello(name) => write!(f, "Hello from {name}"),
+            Message::TextFragment {
+                message_id,
+                index,
+                count,
+                ..
+            } => write!(f, "TextFragment({message_id}, {index}/{count})"),
         }
     }
 }
 
+// Peers are only ever bound to IPv4 loopback addresses in this app, so the
+// wire format only needs to carry 4 octets plus a port.
+fn encode_addr(addr: SocketAddr) -> [u8; 6] {
+    let SocketAddr::V4(addr) = addr else {
+        panic!("IPv6 peers are not supported")
+    };
+
+    let mut bytes = [0; 6];
+    bytes[..4].copy_from_slice(&addr.ip().octets());
+    bytes[4..].copy_from_slice(&addr.port().to_be_bytes());
+    bytes
+}
+
+fn decode_addr(bytes: &[u8]) -> Result<SocketAddr> {
+    ensure!(bytes.len() == 6, "Malformed peer address");
+    let ip = Ipv4Addr::new(bytes[0], bytes[1], bytes[2], bytes[3]);
+    let port = u16::from_be_bytes([bytes[4], bytes[5]]);
+    Ok(SocketAddr::from((ip, port)))
+}
+
+// A string is framed as a u32 big-endian byte length followed by its UTF-8
+// bytes; it's always the last field of whatever message contains it, so
+// `bytes` is expected to hold nothing beyond it.
+fn encode_string(vector: &mut Vec<u8>, value: &str) {
+    vector.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    vector.extend_from_slice(value.as_bytes());
+}
+
+fn decode_string(bytes: &[u8]) -> Result<String> {
+    ensure!(bytes.len() >= 4, "Truncated string length");
+    let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+    ensure!(bytes.len() == 4 + len, "Malformed string");
+    String::from_utf8(bytes[4..].to_vec()).map_err(|_| anyhow!("Invalid UTF-8 in message"))
+}
+
 impl Message {
     pub fn into_bytes(self) -> Vec<u8> {
         match self {
-            Message::TryConnect => vec![0, 1],
-            Message::SuccesfullyConnected => vec![0, 2],
+            Message::TryConnect(public_key) => {
+                let mut vector = vec![0, 1];
+                vector.extend_from_slice(&public_key);
+                vector
+            }
+            Message::SuccesfullyConnected(public_key) => {
+                let mut vector = vec![0, 2];
+                vector.extend_from_slice(&public_key);
+                vector
+            }
             Message::Unexpected => vec![0, 3],
             Message::Disconnect => vec![0, 4],
             Message::SuccesfullyDisonnected => vec![0, 5],
-            Message::Text(message) => {
+            Message::Text(text) => {
                 let mut vector = vec![1];
-                vector.append(&mut message.as_bytes().to_owned());
-                vector.push(0);
+                encode_string(&mut vector, &text);
+                vector
+            }
+            Message::Ack(seq) => {
+                let mut vector = vec![2];
+                vector.extend_from_slice(&seq.to_be_bytes());
+                vector
+            }
+            Message::Join(addr) => {
+                let mut vector = vec![3];
+                vector.extend_from_slice(&encode_addr(addr));
+                vector
+            }
+            Message::Leave(addr) => {
+                let mut vector = vec![4];
+                vector.extend_from_slice(&encode_addr(addr));
+                vector
+            }
+            Message::Roster(addrs) => {
+                let mut vector = vec![5];
+                vector.extend_from_slice(&(addrs.len() as u16).to_be_bytes());
+                for addr in addrs {
+                    vector.extend_from_slice(&encode_addr(addr));
+                }
+                vector
+            }
+            Message::Hello(name) => {
+                let mut vector = vec![6];
+                encode_string(&mut vector, &name);
+                vector
+            }
+            Message::TextFragment {
+                message_id,
+                index,
+                count,
+                chunk,
+            } => {
+                let mut vector = vec![7];
+                vector.extend_from_slice(&message_id.to_be_bytes());
+                vector.extend_from_slice(&index.to_be_bytes());
+                vector.extend_from_slice(&count.to_be_bytes());
+                encode_string(&mut vector, &chunk);
                 vector
             }
         }
     }
 
     pub fn from_bytes(value: &[u8]) -> Result<Self> {
+        ensure!(!value.is_empty(), "Empty datagram");
+
         let message = match value[0] {
-            0 => match value[1] {
-                1 => Self::TryConnect,
-                2 => Self::SuccesfullyConnected,
-                3 => Self::Unexpected,
-                4 => Self::Disconnect,
-                5 => Self::SuccesfullyDisonnected,
-                _ => bail!("Wrong service message type"),
-            },
-            1 => Self::Text(String::from_utf8_lossy(&value[1..value.len() - 1]).into()),
+            0 => {
+                ensure!(value.len() >= 2, "Truncated service message");
+                match value[1] {
+                    1 | 2 => {
+                        ensure!(value.len() == 34, "Malformed handshake message");
+                        let mut public_key = [0; 32];
+                        public_key.copy_from_slice(&value[2..34]);
+                        if value[1] == 1 {
+                            Self::TryConnect(public_key)
+                        } else {
+                            Self::SuccesfullyConnected(public_key)
+                        }
+                    }
+                    3 => Self::Unexpected,
+                    4 => Self::Disconnect,
+                    5 => Self::SuccesfullyDisonnected,
+                    _ => bail!("Wrong service message type"),
+                }
+            }
+            1 => Self::Text(decode_string(&value[1..])?),
+            2 => {
+                ensure!(value.len() == 5, "Malformed Ack message");
+                let mut seq = [0; 4];
+                seq.copy_from_slice(&value[1..5]);
+                Self::Ack(u32::from_be_bytes(seq))
+            }
+            3 => Self::Join(decode_addr(&value[1..])?),
+            4 => Self::Leave(decode_addr(&value[1..])?),
+            5 => {
+                ensure!(value.len() >= 3, "Truncated roster");
+                let mut count = [0; 2];
+                count.copy_from_slice(&value[1..3]);
+                let count = u16::from_be_bytes(count) as usize;
+
+                ensure!(value.len() == 3 + count * 6, "Malformed roster");
+                let addrs = value[3..]
+                    .chunks_exact(6)
+                    .map(decode_addr)
+                    .collect::<Result<_>>()?;
+
+                Self::Roster(addrs)
+            }
+            6 => Self::Hello(decode_string(&value[1..])?),
+            7 => {
+                ensure!(value.len() >= 13, "Truncated text fragment");
+                let message_id = u32::from_be_bytes(value[1..5].try_into().unwrap());
+                let index = u32::from_be_bytes(value[5..9].try_into().unwrap());
+                let count = u32::from_be_bytes(value[9..13].try_into().unwrap());
+                let chunk = decode_string(&value[13..])?;
+                Self::TextFragment {
+                    message_id,
+                    index,
+                    count,
+                    chunk,
+                }
+            }
             _ => bail!("Wrong message type"),
         };
         Ok(message)